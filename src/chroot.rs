@@ -0,0 +1,114 @@
+//! Support for `nix-home-fs run`: runs a program with the caller's real
+//! `HOME/nix` bind-mounted over `/nix`, for tools that need `/nix/store`
+//! to be an actual directory rather than the per-user symlink this FS exposes.
+//!
+//! This mirrors the approach `nix run`'s chroot helper uses: `unshare(CLONE_NEWUSER)`
+//! fails once a process has more than one thread, so we re-exec as a fresh,
+//! single-threaded process before unsharing namespaces.
+
+use crate::get_uid_home_dir;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{execvp, getgid, getuid};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Argument used to re-exec the binary into the hidden chroot-helper mode.
+pub const RUN_IN_CHROOT_ARG: &str = "__run_in_chroot";
+
+/// Entry point for `nix-home-fs run -- <cmd> <args...>`.
+///
+/// `unshare(CLONE_NEWUSER)` can fail with `EINVAL` in a multithreaded process, and we
+/// can't guarantee the current process is single-threaded (clap, logging, etc. may have
+/// spawned threads), so we re-exec ourselves into [`RUN_IN_CHROOT_ARG`] mode, which starts
+/// out single-threaded and does the actual namespace setup.
+pub fn run(cmd: Vec<String>) -> Result<()> {
+    if cmd.is_empty() {
+        bail!("`nix-home-fs run` requires a command, e.g. `nix-home-fs run -- bash`");
+    }
+    let exe = std::env::current_exe().context("resolving path to the current executable")?;
+    let err = Command::new(exe)
+        .arg(RUN_IN_CHROOT_ARG)
+        .arg("--")
+        .args(&cmd)
+        .exec();
+    Err(err).context("failed to re-exec into the chroot helper")
+}
+
+/// The hidden helper mode re-exec'd into by [`run`]. Unshares a user and mount namespace,
+/// bind-mounts the real `HOME/nix` over `/nix`, and `execvp`s `cmd`.
+pub fn run_in_chroot(cmd: Vec<String>) -> Result<()> {
+    if cmd.is_empty() {
+        bail!("internal error: chroot helper invoked with no command");
+    }
+
+    let uid = getuid().as_raw();
+    let gid = getgid().as_raw();
+    let home = get_uid_home_dir(uid)
+        .with_context(|| format!("could not resolve a home directory for uid {uid}"))?;
+    let nix_dir = [home.as_slice(), b"/nix"].concat();
+
+    unshare_namespaces(uid, gid)?;
+
+    mount(
+        Some(nix_dir.as_slice()),
+        "/nix",
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .with_context(|| {
+        format!(
+            "bind-mounting {} onto /nix",
+            String::from_utf8_lossy(&nix_dir)
+        )
+    })?;
+
+    let program = CString::new(cmd[0].clone()).context("command contains a NUL byte")?;
+    let args = cmd
+        .iter()
+        .map(|arg| CString::new(arg.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("argument contains a NUL byte")?;
+    let err = execvp(&program, &args).expect_err("execvp only returns on failure");
+    Err(err).with_context(|| format!("failed to exec {:?}", cmd[0]))
+}
+
+/// Unshares a private user+mount namespace, mapping the invoking uid/gid to themselves so
+/// the bind mount below is visible without requiring the caller to be root. Falls back to a
+/// mount-namespace-only unshare (which itself needs `CAP_SYS_ADMIN`, e.g. running as root)
+/// on kernels where unprivileged user namespaces are disabled.
+fn unshare_namespaces(uid: u32, gid: u32) -> Result<()> {
+    match unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS) {
+        Ok(()) => {
+            fs::write("/proc/self/setgroups", "deny").context("writing /proc/self/setgroups")?;
+            fs::write("/proc/self/uid_map", format!("{uid} {uid} 1"))
+                .context("writing /proc/self/uid_map")?;
+            fs::write("/proc/self/gid_map", format!("{gid} {gid} 1"))
+                .context("writing /proc/self/gid_map")?;
+        }
+        Err(Errno::EPERM) | Err(Errno::EINVAL) => {
+            unshare(CloneFlags::CLONE_NEWNS).context(
+                "unshare(CLONE_NEWUSER) was refused (unprivileged user namespaces are likely \
+                 disabled on this kernel) and the mount-namespace-only fallback also failed; \
+                 re-run as root, or enable unprivileged user namespaces, to use `nix-home-fs run`",
+            )?;
+        }
+        Err(e) => return Err(e).context("unshare(CLONE_NEWUSER | CLONE_NEWNS) failed"),
+    }
+
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .context("making the mount namespace private")?;
+
+    Ok(())
+}
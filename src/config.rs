@@ -0,0 +1,109 @@
+//! Optional TOML config file describing the per-user symlinks this filesystem exposes.
+//!
+//! Each entry has a `name` (the file name shown under the mount point) and a `target`
+//! template, where the literal `{home}` is replaced with the calling user's home directory
+//! at lookup time. Without a config file, the original `store` and `var` entries are used.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(rename = "entry", default = "default_entries")]
+    pub entries: Vec<Entry>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            entries: default_entries(),
+        }
+    }
+}
+
+fn default_entries() -> Vec<Entry> {
+    vec![
+        Entry {
+            name: "store".to_string(),
+            target: "{home}/nix/store".to_string(),
+        },
+        Entry {
+            name: "var".to_string(),
+            target: "{home}/nix/var".to_string(),
+        },
+    ]
+}
+
+/// Loads the config from `path`, or the default `store`/`var` layout if `path` is `None`.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let Some(path) = path else {
+        return Ok(Config::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading config file {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parsing config file {}", path.display()))
+}
+
+/// Expands `{home}` in `template` with `home` (raw bytes, since home directories need not be
+/// valid UTF-8).
+pub fn expand(template: &str, home: &[u8]) -> Vec<u8> {
+    let mut parts = template.split("{home}");
+    let mut out = parts.next().unwrap_or("").as_bytes().to_vec();
+    for part in parts {
+        out.extend_from_slice(home);
+        out.extend_from_slice(part.as_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_substitutes_home() {
+        assert_eq!(expand("{home}/nix/store", b"/home/alice"), b"/home/alice/nix/store");
+    }
+
+    #[test]
+    fn expand_handles_multiple_occurrences() {
+        assert_eq!(
+            expand("{home}/a/{home}/b", b"/home/alice"),
+            b"/home/alice/a//home/alice/b"
+        );
+    }
+
+    #[test]
+    fn expand_is_a_no_op_without_the_placeholder() {
+        assert_eq!(expand("/nix/store", b"/home/alice"), b"/nix/store");
+    }
+
+    #[test]
+    fn expand_passes_through_non_utf8_home() {
+        let home = b"/home/\xff\xfe";
+        assert_eq!(expand("{home}/nix/store", home), b"/home/\xff\xfe/nix/store");
+    }
+
+    #[test]
+    fn default_entries_are_store_and_var() {
+        let config = Config::default();
+        assert_eq!(config.entries.len(), 2);
+        assert_eq!(config.entries[0].name, "store");
+        assert_eq!(config.entries[0].target, "{home}/nix/store");
+        assert_eq!(config.entries[1].name, "var");
+        assert_eq!(config.entries[1].target, "{home}/nix/var");
+    }
+
+    #[test]
+    fn load_with_no_path_returns_the_default() {
+        let config = load(None).unwrap();
+        assert_eq!(config.entries.len(), 2);
+    }
+}
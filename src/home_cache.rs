@@ -0,0 +1,114 @@
+//! A small TTL cache mapping uid to its resolved home directory, so a FUSE request from a
+//! user we've already seen doesn't need another `getpwuid`/NSS lookup (via
+//! [`crate::get_uid_home_dir`]). This matters on hosts backed by LDAP/SSSD, where each NSS
+//! lookup can be a network round trip.
+
+use crate::get_uid_home_dir;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type CacheEntry = (Option<Vec<u8>>, Instant);
+
+pub struct HomeDirCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<u32, CacheEntry>>,
+}
+
+impl HomeDirCache {
+    pub fn new(ttl: Duration) -> Self {
+        HomeDirCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `uid`'s home directory, resolving and caching it if there's no entry yet or
+    /// the cached one has expired. `None` means `uid` has no resolvable home directory.
+    pub fn get(&self, uid: u32) -> Option<Vec<u8>> {
+        self.get_with(uid, get_uid_home_dir)
+    }
+
+    /// Same as [`Self::get`], but resolving misses with `resolve` instead of
+    /// [`get_uid_home_dir`], so tests can exercise the caching/TTL logic without going through
+    /// NSS.
+    fn get_with(&self, uid: u32, resolve: impl FnOnce(u32) -> Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        {
+            let entries = self.entries.lock().unwrap();
+            let fresh = entries
+                .get(&uid)
+                .filter(|(_, resolved_at)| now.duration_since(*resolved_at) < self.ttl);
+            if let Some((home, _)) = fresh {
+                return home.clone();
+            }
+        }
+        let home = resolve(uid);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(uid, (home.clone(), now));
+        home
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn caches_a_resolved_home_until_ttl_expires() {
+        let cache = HomeDirCache::new(Duration::from_millis(20));
+        let calls = AtomicUsize::new(0);
+        let resolve = |_uid| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Some(b"/home/alice".to_vec())
+        };
+
+        assert_eq!(cache.get_with(1, resolve), Some(b"/home/alice".to_vec()));
+        assert_eq!(cache.get_with(1, resolve), Some(b"/home/alice".to_vec()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "second call should hit the cache");
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get_with(1, resolve), Some(b"/home/alice".to_vec()));
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            2,
+            "call after the TTL elapsed should re-resolve"
+        );
+    }
+
+    #[test]
+    fn negatively_caches_an_unresolvable_uid() {
+        let cache = HomeDirCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let resolve = |_uid| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            None
+        };
+
+        assert_eq!(cache.get_with(1, resolve), None);
+        assert_eq!(cache.get_with(1, resolve), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "a None result should also be cached");
+    }
+
+    #[test]
+    fn caches_each_uid_independently() {
+        let cache = HomeDirCache::new(Duration::from_secs(60));
+        assert_eq!(
+            cache.get_with(1, |_| Some(b"/home/alice".to_vec())),
+            Some(b"/home/alice".to_vec())
+        );
+        assert_eq!(
+            cache.get_with(2, |_| Some(b"/home/bob".to_vec())),
+            Some(b"/home/bob".to_vec())
+        );
+        // uid 2 now has its own fresh entry, served without resolving again.
+        assert_eq!(
+            cache.get_with(2, |_| panic!("should not re-resolve a fresh entry")),
+            Some(b"/home/bob".to_vec())
+        );
+    }
+}
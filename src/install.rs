@@ -0,0 +1,265 @@
+//! `nix-home-fs install`/`uninstall`: provisions (or tears down) a boot-time mount of this
+//! filesystem, mirroring what the Nix installer does for its daemon: an `/etc/fstab` entry
+//! plus an optional initial `mount` on Linux, or a LaunchDaemon on macOS.
+//!
+//! Each platform-specific step is an [`Action`]; `uninstall` is just `install`'s plan walked
+//! in reverse, calling `revert` instead of `execute`, so the two commands can't drift apart.
+//! `uninstall` builds that plan without the initial-mount step, so it does not unmount a
+//! filesystem that `install --mount` mounted.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+trait Action {
+    fn execute(&self) -> Result<()>;
+    fn revert(&self) -> Result<()>;
+}
+
+pub fn install(mountpoint: &Path, run_mount: bool) -> Result<()> {
+    for action in plan(mountpoint, run_mount)? {
+        action.execute()?;
+    }
+    Ok(())
+}
+
+pub fn uninstall(mountpoint: &Path) -> Result<()> {
+    for action in plan(mountpoint, false)?.into_iter().rev() {
+        action.revert()?;
+    }
+    Ok(())
+}
+
+fn plan(mountpoint: &Path, run_mount: bool) -> Result<Vec<Box<dyn Action>>> {
+    let exe = std::env::current_exe().context("resolving path to the current executable")?;
+
+    if cfg!(target_os = "macos") {
+        Ok(vec![Box::new(LaunchDaemonPlist::new(&exe, mountpoint))])
+    } else {
+        let mut plan: Vec<Box<dyn Action>> = vec![Box::new(FstabEntry::new(&exe, mountpoint))];
+        if run_mount {
+            plan.push(Box::new(RunMount::new(mountpoint)));
+        }
+        Ok(plan)
+    }
+}
+
+const FSTAB_PATH: &str = "/etc/fstab";
+const FSTAB_MARKER: &str =
+    "# Added by `nix-home-fs install`; run `nix-home-fs uninstall` to remove";
+
+/// The `/etc/fstab` line shown in the CLI docs, preceded by a marker comment so `uninstall`
+/// can find and remove exactly what `install` added.
+struct FstabEntry {
+    line: String,
+}
+
+impl FstabEntry {
+    fn new(exe: &Path, mountpoint: &Path) -> Self {
+        FstabEntry {
+            line: format!(
+                "dummy-src {} fuse.{} dummy-opts 0 0",
+                mountpoint.display(),
+                exe.display()
+            ),
+        }
+    }
+}
+
+impl Action for FstabEntry {
+    fn execute(&self) -> Result<()> {
+        let existing = fs::read_to_string(FSTAB_PATH).unwrap_or_default();
+        let Some(updated) = fstab_with_entry_added(&existing, &self.line) else {
+            return Ok(());
+        };
+        fs::write(FSTAB_PATH, updated).with_context(|| format!("writing {FSTAB_PATH}"))
+    }
+
+    fn revert(&self) -> Result<()> {
+        let Ok(existing) = fs::read_to_string(FSTAB_PATH) else {
+            return Ok(());
+        };
+        let updated = fstab_with_entry_removed(&existing, &self.line);
+        fs::write(FSTAB_PATH, updated).with_context(|| format!("writing {FSTAB_PATH}"))
+    }
+}
+
+/// Returns `existing` with the marker comment and `line` appended, or `None` if `line` is
+/// already present (so `execute` is idempotent).
+fn fstab_with_entry_added(existing: &str, line: &str) -> Option<String> {
+    if existing.contains(line) {
+        return None;
+    }
+    let mut updated = existing.to_string();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(FSTAB_MARKER);
+    updated.push('\n');
+    updated.push_str(line);
+    updated.push('\n');
+    Some(updated)
+}
+
+/// Returns `existing` with the marker comment + `line` pair added by
+/// [`fstab_with_entry_added`] removed, leaving everything else untouched.
+fn fstab_with_entry_removed(existing: &str, line: &str) -> String {
+    let mut kept = Vec::new();
+    let mut lines = existing.lines().peekable();
+    while let Some(current) = lines.next() {
+        if current == FSTAB_MARKER && lines.peek() == Some(&line) {
+            lines.next();
+            continue;
+        }
+        kept.push(current);
+    }
+    let mut updated = kept.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_then_remove_round_trips_to_the_original() {
+        let original = "existing-src /existing none defaults 0 0\n";
+        let line = "dummy-src /nix fuse./usr/bin/nix-home-fs dummy-opts 0 0";
+
+        let added = fstab_with_entry_added(original, line).expect("entry not yet present");
+        assert!(added.contains(FSTAB_MARKER));
+        assert!(added.contains(line));
+
+        let removed = fstab_with_entry_removed(&added, line);
+        assert_eq!(removed, original);
+    }
+
+    #[test]
+    fn add_is_idempotent() {
+        let line = "dummy-src /nix fuse./usr/bin/nix-home-fs dummy-opts 0 0";
+        let added = fstab_with_entry_added("", line).unwrap();
+        assert!(fstab_with_entry_added(&added, line).is_none());
+    }
+
+    #[test]
+    fn remove_on_an_empty_file_is_a_no_op() {
+        let line = "dummy-src /nix fuse./usr/bin/nix-home-fs dummy-opts 0 0";
+        assert_eq!(fstab_with_entry_removed("", line), "");
+    }
+
+    #[test]
+    fn add_to_an_empty_file_produces_just_marker_and_line() {
+        let line = "dummy-src /nix fuse./usr/bin/nix-home-fs dummy-opts 0 0";
+        let added = fstab_with_entry_added("", line).unwrap();
+        assert_eq!(added, format!("{FSTAB_MARKER}\n{line}\n"));
+    }
+}
+
+/// Runs (or undoes) the initial `mount`/`umount` of `mountpoint`, for callers that want
+/// `install` to take effect immediately rather than at the next boot.
+struct RunMount {
+    mountpoint: PathBuf,
+}
+
+impl RunMount {
+    fn new(mountpoint: &Path) -> Self {
+        RunMount {
+            mountpoint: mountpoint.to_owned(),
+        }
+    }
+}
+
+impl Action for RunMount {
+    fn execute(&self) -> Result<()> {
+        let status = Command::new("mount")
+            .arg(&self.mountpoint)
+            .status()
+            .with_context(|| format!("running `mount {}`", self.mountpoint.display()))?;
+        if !status.success() {
+            bail!("`mount {}` failed: {status}", self.mountpoint.display());
+        }
+        Ok(())
+    }
+
+    fn revert(&self) -> Result<()> {
+        let status = Command::new("umount")
+            .arg(&self.mountpoint)
+            .status()
+            .with_context(|| format!("running `umount {}`", self.mountpoint.display()))?;
+        if !status.success() {
+            bail!("`umount {}` failed: {status}", self.mountpoint.display());
+        }
+        Ok(())
+    }
+}
+
+const LAUNCHD_LABEL: &str = "org.nixos.nix-home-fs";
+
+/// A LaunchDaemon that runs `nix-home-fs --foreground <mountpoint>` at boot, loaded
+/// immediately via `launchctl load`.
+struct LaunchDaemonPlist {
+    path: PathBuf,
+    contents: String,
+}
+
+impl LaunchDaemonPlist {
+    fn new(exe: &Path, mountpoint: &Path) -> Self {
+        let contents = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>--foreground</string>
+        <string>{mountpoint}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+</dict>
+</plist>
+"#,
+            exe = exe.display(),
+            mountpoint = mountpoint.display(),
+        );
+        LaunchDaemonPlist {
+            path: PathBuf::from(format!("/Library/LaunchDaemons/{LAUNCHD_LABEL}.plist")),
+            contents,
+        }
+    }
+}
+
+impl Action for LaunchDaemonPlist {
+    fn execute(&self) -> Result<()> {
+        fs::write(&self.path, &self.contents)
+            .with_context(|| format!("writing {}", self.path.display()))?;
+        let status = Command::new("launchctl")
+            .arg("load")
+            .arg(&self.path)
+            .status()
+            .context("running `launchctl load`")?;
+        if !status.success() {
+            bail!("`launchctl load {}` failed: {status}", self.path.display());
+        }
+        Ok(())
+    }
+
+    fn revert(&self) -> Result<()> {
+        // Best-effort: the daemon may already be unloaded (e.g. after a reboot).
+        let _ = Command::new("launchctl").arg("unload").arg(&self.path).status();
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("removing {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,27 @@
+mod chroot;
+mod config;
+mod home_cache;
+mod install;
+mod pool;
+
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use config::Config;
 use daemonize::Daemonize;
 use fuser::{
     FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
     Request,
 };
+use home_cache::HomeDirCache;
 use libc::ENOENT;
 use nix::unistd::User;
+use pool::WorkerPool;
 use std::ffi::OsStr;
 use std::os::unix::prelude::OsStrExt;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, UNIX_EPOCH};
 
-const TTL: Duration = Duration::from_secs(1);
-
 fn get_dir_attr(uid: u32, gid: u32) -> FileAttr {
     FileAttr {
         ino: 1,
@@ -34,7 +42,7 @@ fn get_dir_attr(uid: u32, gid: u32) -> FileAttr {
     }
 }
 
-fn get_uid_home_dir(uid: u32) -> Option<Vec<u8>> {
+pub(crate) fn get_uid_home_dir(uid: u32) -> Option<Vec<u8>> {
     Some(
         User::from_uid(uid.into())
             .ok()??
@@ -45,15 +53,53 @@ fn get_uid_home_dir(uid: u32) -> Option<Vec<u8>> {
     )
 }
 
-fn get_store_target(uid: u32) -> Vec<u8> {
-    let home = get_uid_home_dir(uid).unwrap_or("UNKNOWN_HOME".into());
-    [home, b"/nix/store".to_vec()].concat()
+/// Root has no home directory suitable for resolving `{home}`-templated symlinks, but
+/// root-run daemons still need a working `/nix`, so it's special-cased to `root_home` instead
+/// of going through [`HomeDirCache`].
+fn is_root_user(uid: u32) -> bool {
+    uid == 0
 }
 
-fn get_store_attr(uid: u32, gid: u32) -> FileAttr {
-    FileAttr {
-        ino: 2,
-        size: get_store_target(uid).len().try_into().unwrap(),
+/// Resolves `target_template` for `uid`, or `None` if `uid`'s home directory can't be
+/// resolved, in which case callers should report `ENOENT` rather than handing out a target
+/// built from a missing home directory.
+///
+/// Root has no home directory to expand `target_template` against, so its target is instead
+/// `root_home/name` directly (e.g. `root_home` defaults to `/nix-real`, so `store` resolves
+/// to `/nix-real/store`), rather than nesting under a template meant for per-user homes.
+fn get_symlink_target(
+    target_template: &str,
+    name: &str,
+    uid: u32,
+    home_cache: &HomeDirCache,
+    root_home: &[u8],
+) -> Option<Vec<u8>> {
+    if is_root_user(uid) {
+        let mut target = root_home.to_vec();
+        target.push(b'/');
+        target.extend_from_slice(name.as_bytes());
+        return Some(target);
+    }
+    let home = home_cache.get(uid)?;
+    Some(config::expand(target_template, &home))
+}
+
+fn get_symlink_attr(
+    ino: u64,
+    target_template: &str,
+    name: &str,
+    uid: u32,
+    gid: u32,
+    home_cache: &HomeDirCache,
+    root_home: &[u8],
+) -> Option<FileAttr> {
+    let size = get_symlink_target(target_template, name, uid, home_cache, root_home)?
+        .len()
+        .try_into()
+        .unwrap();
+    Some(FileAttr {
+        ino,
+        size,
         blocks: 1,
         atime: UNIX_EPOCH,
         mtime: UNIX_EPOCH,
@@ -67,60 +113,150 @@ fn get_store_attr(uid: u32, gid: u32) -> FileAttr {
         rdev: 0,
         flags: 0,
         blksize: 512,
-    }
+    })
 }
 
-fn get_var_target(uid: u32) -> Vec<u8> {
-    let home = get_uid_home_dir(uid).unwrap_or("UNKNOWN_HOME".into());
-    [home, b"/nix/var".to_vec()].concat()
+/// A symlink exposed at the root of the mount, built from the config's entries. Inodes start
+/// at 2 (1 is the root directory) in config order.
+struct SymlinkEntry {
+    ino: u64,
+    name: Arc<str>,
+    target_template: Arc<str>,
 }
 
-fn get_var_attr(uid: u32, gid: u32) -> FileAttr {
-    FileAttr {
-        ino: 3,
-        size: get_var_target(uid).len().try_into().unwrap(),
-        blocks: 1,
-        atime: UNIX_EPOCH,
-        mtime: UNIX_EPOCH,
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind: FileType::Symlink,
-        perm: 0o777,
-        nlink: 1,
-        uid,
-        gid,
-        rdev: 0,
-        flags: 0,
-        blksize: 512,
-    }
+struct NixHomeFS {
+    /// How long the kernel may cache attributes and directory entries we return.
+    ttl: Duration,
+    /// When set, FUSE requests that need to resolve a uid's home directory are handed off to
+    /// a worker thread instead of being served inline from the single dispatch loop.
+    pool: Option<WorkerPool>,
+    /// The symlinks exposed at the root of the mount.
+    entries: Vec<SymlinkEntry>,
+    /// Caches each uid's resolved home directory, to avoid a `getpwuid`/NSS lookup on every
+    /// request from a user we've already seen.
+    home_cache: Arc<HomeDirCache>,
+    /// Directory holding the real, system-wide targets (e.g. `root_home/store`) handed out
+    /// to uid 0, which has no home directory suitable for expanding the per-user templates.
+    root_home: Arc<[u8]>,
 }
 
-struct NixHomeFS;
+impl NixHomeFS {
+    fn new(
+        ttl: Duration,
+        threads: usize,
+        config: Config,
+        home_cache_ttl: Duration,
+        root_home: Vec<u8>,
+    ) -> Self {
+        let pool = (threads > 1).then(|| WorkerPool::new(threads));
+        let entries = config
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| SymlinkEntry {
+                ino: 2 + i as u64,
+                name: Arc::from(entry.name),
+                target_template: Arc::from(entry.target),
+            })
+            .collect();
+        NixHomeFS {
+            ttl,
+            pool,
+            entries,
+            home_cache: Arc::new(HomeDirCache::new(home_cache_ttl)),
+            root_home: Arc::from(root_home),
+        }
+    }
+
+    /// Runs `f(uid, gid, reply)` on a worker thread if a pool is configured, or inline
+    /// otherwise, matching the single-threaded behavior this filesystem started with.
+    fn dispatch<R, F>(&self, req: &Request, reply: R, f: F)
+    where
+        R: Send + 'static,
+        F: FnOnce(u32, u32, R) + Send + 'static,
+    {
+        let uid = req.uid();
+        let gid = req.gid();
+        match &self.pool {
+            Some(pool) => pool.execute(move || f(uid, gid, reply)),
+            None => f(uid, gid, reply),
+        }
+    }
+
+    fn find_entry_by_name(&self, name: &str) -> Option<&SymlinkEntry> {
+        self.entries.iter().find(|e| &*e.name == name)
+    }
+
+    fn find_entry_by_ino(&self, ino: u64) -> Option<&SymlinkEntry> {
+        self.entries.iter().find(|e| e.ino == ino)
+    }
+}
 
 impl Filesystem for NixHomeFS {
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match (parent, name.to_str()) {
-            (1, Some("store")) => reply.entry(&TTL, &get_store_attr(req.uid(), req.gid()), 0),
-            (1, Some("var")) => reply.entry(&TTL, &get_var_attr(req.uid(), req.gid()), 0),
-            _ => reply.error(ENOENT),
+        let ttl = self.ttl;
+        if parent != 1 {
+            reply.error(ENOENT);
+            return;
         }
+        let Some(entry) = name.to_str().and_then(|name| self.find_entry_by_name(name)) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let ino = entry.ino;
+        let name = entry.name.clone();
+        let target_template = entry.target_template.clone();
+        let home_cache = Arc::clone(&self.home_cache);
+        let root_home = Arc::clone(&self.root_home);
+        self.dispatch(req, reply, move |uid, gid, reply| {
+            match get_symlink_attr(ino, &target_template, &name, uid, gid, &home_cache, &root_home)
+            {
+                Some(attr) => reply.entry(&ttl, &attr, 0),
+                None => reply.error(ENOENT),
+            }
+        });
     }
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
-        match ino {
-            1 => reply.attr(&TTL, &get_dir_attr(req.uid(), req.gid())),
-            2 => reply.attr(&TTL, &get_store_attr(req.uid(), req.gid())),
-            3 => reply.attr(&TTL, &get_var_attr(req.uid(), req.gid())),
-            _ => reply.error(ENOENT),
+        let ttl = self.ttl;
+        if ino == 1 {
+            self.dispatch(req, reply, move |uid, gid, reply| {
+                reply.attr(&ttl, &get_dir_attr(uid, gid))
+            });
+            return;
         }
+        let Some(entry) = self.find_entry_by_ino(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let name = entry.name.clone();
+        let target_template = entry.target_template.clone();
+        let home_cache = Arc::clone(&self.home_cache);
+        let root_home = Arc::clone(&self.root_home);
+        self.dispatch(req, reply, move |uid, gid, reply| {
+            match get_symlink_attr(ino, &target_template, &name, uid, gid, &home_cache, &root_home)
+            {
+                Some(attr) => reply.attr(&ttl, &attr),
+                None => reply.error(ENOENT),
+            }
+        });
     }
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
-        match ino {
-            2 => reply.data(get_store_target(req.uid()).as_slice()),
-            3 => reply.data(get_var_target(req.uid()).as_slice()),
-            _ => reply.error(ENOENT),
-        }
+        let Some(entry) = self.find_entry_by_ino(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let name = entry.name.clone();
+        let target_template = entry.target_template.clone();
+        let home_cache = Arc::clone(&self.home_cache);
+        let root_home = Arc::clone(&self.root_home);
+        self.dispatch(req, reply, move |uid, _gid, reply| {
+            match get_symlink_target(&target_template, &name, uid, &home_cache, &root_home) {
+                Some(target) => reply.data(target.as_slice()),
+                None => reply.error(ENOENT),
+            }
+        });
     }
 
     fn readdir(
@@ -136,12 +272,15 @@ impl Filesystem for NixHomeFS {
             return;
         }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::Symlink, "store"),
-            (3, FileType::Symlink, "var"),
+        let mut entries = vec![
+            (1, FileType::Directory, ".".to_string()),
+            (1, FileType::Directory, "..".to_string()),
         ];
+        entries.extend(
+            self.entries
+                .iter()
+                .map(|e| (e.ino, FileType::Symlink, e.name.to_string())),
+        );
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
             // i + 1 means the index of the next entry
@@ -166,7 +305,22 @@ impl Filesystem for NixHomeFS {
 ///   dummy-src /nix fuse./path/to/nix-home-fs dummy-opts 0 0
 /// And run:
 ///   sudo mount /nix
+/// -
+/// The symlinks exposed at the root of the mount can be customized with --config; see
+/// `Config` in src/config.rs for the file format. Without --config, the default `store` and
+/// `var` entries are used.
+/// -
+/// To run a program against a real (non-symlink) /nix/store, use:
+///   nix-home-fs run -- <cmd> [args...]
+/// -
+/// To set this up to mount automatically at boot, instead of editing /etc/fstab yourself:
+///   sudo nix-home-fs install
+/// And to remove it again:
+///   sudo nix-home-fs uninstall
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Mount options. Currently only for compatibility with `mount -t fuse.<path>`
     #[arg(short, value_name = "OPTS")]
     opts: Option<String>,
@@ -175,30 +329,129 @@ struct Cli {
     #[arg(long)]
     foreground: bool,
 
+    /// Allow users other than the one who mounted the filesystem to access it. Pass
+    /// `--allow-other=false` (or `--no-allow-other`) to restrict access to the mounting user
+    #[arg(
+        long,
+        action = clap::ArgAction::Set,
+        default_value_t = true,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    allow_other: bool,
+
+    /// Shorthand for `--allow-other=false`
+    #[arg(long, conflicts_with = "allow_other", action = clap::ArgAction::SetTrue)]
+    no_allow_other: bool,
+
+    /// Number of worker threads used to resolve filesystem requests (e.g. home directory
+    /// lookups) concurrently off the single-threaded `fuser` dispatch loop; see `pool`. With
+    /// 1 (the default) or 0, no worker threads are started and requests are served inline
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// How long, in seconds, the kernel may cache attributes and directory entries
+    #[arg(long, default_value_t = 1)]
+    attr_timeout: u64,
+
+    /// Path to a TOML config file defining the symlinks to expose (defaults to just `store`
+    /// and `var`)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// How long, in seconds, to cache a resolved uid -> home directory mapping before
+    /// looking it up again
+    #[arg(long, default_value_t = 60)]
+    home_cache_ttl: u64,
+
+    /// Directory holding the real targets handed to root (uid 0) instead of the per-user
+    /// template expansion, since root has no home directory suitable for that purpose: root's
+    /// `store` symlink resolves to `root_home/store`, `var` to `root_home/var`, and so on for
+    /// any configured entry name. Any other uid whose home directory can't be resolved gets
+    /// ENOENT instead of a fallback.
+    #[arg(long, default_value = "/nix-real", value_name = "PATH")]
+    root_home: PathBuf,
+
     /// If only one parameter is given, the mountpoint. If two parameters are given, ignored, for compatibility with `mount -t fuse.<path>`
-    dev_or_mountpoint: PathBuf,
+    dev_or_mountpoint: Option<PathBuf>,
 
     /// If given, where to mount the filesystem
     mountpoint: Option<PathBuf>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Run a program with the caller's real `HOME/nix` bind-mounted over `/nix`, for tools
+    /// that need `/nix/store` to be a real directory rather than the per-user symlink
+    Run {
+        /// The program and arguments to run, e.g. `nix-home-fs run -- bash`
+        #[arg(last = true, required = true)]
+        cmd: Vec<String>,
+    },
+
+    /// Hidden re-exec target used internally by `run` to reach a single-threaded process
+    /// before unsharing namespaces. Not meant to be invoked directly.
+    #[command(name = "__run_in_chroot", hide = true)]
+    RunInChroot {
+        #[arg(last = true, required = true)]
+        cmd: Vec<String>,
+    },
+
+    /// Set up this filesystem to mount at boot: an `/etc/fstab` entry on Linux (optionally
+    /// mounting it immediately), or a LaunchDaemon on macOS
+    Install {
+        /// Where the filesystem should be mounted
+        #[arg(default_value = "/nix")]
+        mountpoint: PathBuf,
+
+        /// Also mount it right now, instead of waiting for the next boot (Linux only; the
+        /// macOS LaunchDaemon always mounts immediately)
+        #[arg(long)]
+        mount: bool,
+    },
+
+    /// Remove the boot-time setup `install` created. A filesystem mounted by `install --mount`
+    /// is not unmounted
+    Uninstall {
+        /// The mount point `install` was given
+        #[arg(default_value = "/nix")]
+        mountpoint: PathBuf,
+    },
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
     env_logger::init();
-    let mountpoint = cli.mountpoint.unwrap_or(cli.dev_or_mountpoint);
-    let options = vec![
-        MountOption::RO,
-        MountOption::FSName("nix-home-fs".into()),
-        MountOption::AllowOther,
-    ];
-    let mount_fs = || {
-        fuser::mount2(NixHomeFS, &mountpoint, &options)
-    };
-    if cli.foreground {
-        mount_fs()?;
-    } else {
+
+    match cli.command {
+        Some(Command::Run { cmd }) => return chroot::run(cmd),
+        Some(Command::RunInChroot { cmd }) => return chroot::run_in_chroot(cmd),
+        Some(Command::Install { mountpoint, mount }) => return install::install(&mountpoint, mount),
+        Some(Command::Uninstall { mountpoint }) => return install::uninstall(&mountpoint),
+        None => {}
+    }
+
+    let dev_or_mountpoint = cli
+        .dev_or_mountpoint
+        .ok_or_else(|| anyhow::anyhow!("missing mountpoint"))?;
+    let mountpoint = cli.mountpoint.unwrap_or(dev_or_mountpoint);
+    let mut options = vec![MountOption::RO, MountOption::FSName("nix-home-fs".into())];
+    if cli.allow_other && !cli.no_allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    let config = config::load(cli.config.as_deref())?;
+    let fs = NixHomeFS::new(
+        Duration::from_secs(cli.attr_timeout),
+        cli.threads,
+        config,
+        Duration::from_secs(cli.home_cache_ttl),
+        cli.root_home.as_os_str().as_bytes().to_vec(),
+    );
+
+    if !cli.foreground {
         Daemonize::new().start()?;
-        mount_fs()?;
-    };
+    }
+    fuser::mount2(fs, &mountpoint, &options)?;
     Ok(())
 }
@@ -0,0 +1,44 @@
+//! A tiny fixed-size thread pool used to let FUSE requests that do real work (e.g. the NSS
+//! lookup in `get_uid_home_dir`) run off the single read-dispatch loop. `fuser`'s replies are
+//! designed to be handed to another thread and completed later, so a request handler can just
+//! submit a job here and return immediately, letting the dispatch loop read the next request.
+//!
+//! Note this means `--threads` only controls concurrency of the home-directory lookup itself;
+//! requests are still read one at a time from the kernel via `fuser::mount2` rather than through
+//! a spawned multithreaded session, so it doesn't parallelize the read side of the FUSE loop.
+
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    tx: Sender<Job>,
+}
+
+impl WorkerPool {
+    /// Spawns `threads` worker threads sharing a single job queue.
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "a worker pool needs at least one thread");
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = Arc::clone(&rx);
+            thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+        WorkerPool { tx }
+    }
+
+    /// Submits `job` to run on a worker thread.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // The pool's worker threads never exit while `self` is alive, so this can't fail.
+        let _ = self.tx.send(Box::new(job));
+    }
+}